@@ -0,0 +1,528 @@
+//! Parse DTrace provider definition ("D") files into structures that can be rendered as the
+//! Rust and C code needed to wire a process up to those probes.
+// Copyright 2021 Oxide Computer Company
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Errors that can occur while reading or parsing a D provider definition file.
+#[derive(Debug)]
+pub enum Error {
+    /// The source file could not be read.
+    Io(std::io::Error),
+    /// The contents of the source file are not a valid provider definition.
+    Parse(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "could not read provider file: {}", e),
+            Error::Parse(msg) => write!(f, "could not parse provider file: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// The C-level keywords that a mangled provider or probe name must not collide with, since both
+/// end up as part of a bare (non-macro) C symbol name.
+const C_KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while",
+];
+
+/// DTrace mangles dashes in provider and probe names to underscores when generating C symbols,
+/// since a dash isn't a valid character in a C identifier. Guard against the (unlikely but
+/// possible) case where the mangled name collides with a C keyword by appending a trailing
+/// underscore, the same trick `dtrace -h` itself uses for this case.
+fn mangle(name: &str) -> String {
+    let mangled = name.replace('-', "_");
+    if C_KEYWORDS.contains(&mangled.as_str()) {
+        format!("{}_", mangled)
+    } else {
+        mangled
+    }
+}
+
+/// The type of a single probe argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    String,
+}
+
+impl DataType {
+    fn from_d_type(s: &str) -> Option<Self> {
+        match s.trim() {
+            "uint8_t" => Some(DataType::U8),
+            "int8_t" => Some(DataType::I8),
+            "uint16_t" => Some(DataType::U16),
+            "int16_t" => Some(DataType::I16),
+            "uint32_t" => Some(DataType::U32),
+            "int32_t" => Some(DataType::I32),
+            "uint64_t" => Some(DataType::U64),
+            "int64_t" => Some(DataType::I64),
+            "char *" | "char*" | "string" => Some(DataType::String),
+            _ => None,
+        }
+    }
+
+    /// The C type used to pass this argument across the FFI boundary.
+    pub fn to_c_type(self) -> &'static str {
+        match self {
+            DataType::U8 => "uint8_t",
+            DataType::I8 => "int8_t",
+            DataType::U16 => "uint16_t",
+            DataType::I16 => "int16_t",
+            DataType::U32 => "uint32_t",
+            DataType::I32 => "int32_t",
+            DataType::U64 => "uint64_t",
+            DataType::I64 => "int64_t",
+            DataType::String => "char *",
+        }
+    }
+
+    /// The Rust type used to pass this argument across the FFI boundary.
+    pub fn to_rust_type(self) -> &'static str {
+        match self {
+            DataType::U8 => "u8",
+            DataType::I8 => "i8",
+            DataType::U16 => "u16",
+            DataType::I16 => "i16",
+            DataType::U32 => "u32",
+            DataType::I32 => "i32",
+            DataType::U64 => "u64",
+            DataType::I64 => "i64",
+            DataType::String => "*const std::os::raw::c_char",
+        }
+    }
+}
+
+/// A single argument to a probe.
+#[derive(Debug, Clone, Copy)]
+pub struct Argument {
+    ty: DataType,
+}
+
+impl Argument {
+    /// The C type of this argument.
+    pub fn c_type(&self) -> &'static str {
+        self.ty.to_c_type()
+    }
+
+    /// The Rust type of this argument.
+    pub fn rust_type(&self) -> &'static str {
+        self.ty.to_rust_type()
+    }
+}
+
+/// A single probe within a provider, with its argument types.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    name: String,
+    arguments: Vec<Argument>,
+}
+
+impl Probe {
+    /// The probe's name, exactly as written in the D file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// This probe's arguments, in declaration order.
+    pub fn arguments(&self) -> &[Argument] {
+        &self.arguments
+    }
+
+    /// This probe's name, with dashes mangled to underscores the same way DTrace mangles them
+    /// when generating C symbols and macros.
+    pub fn mangled_name(&self) -> String {
+        mangle(&self.name)
+    }
+}
+
+/// A single DTrace provider, containing the probes it defines.
+#[derive(Debug, Clone)]
+pub struct Provider {
+    name: String,
+    probes: Vec<Probe>,
+}
+
+impl Provider {
+    /// The provider's name, exactly as written in the D file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The probes this provider defines, in declaration order.
+    pub fn probes(&self) -> &[Probe] {
+        &self.probes
+    }
+
+    /// This provider's name, with dashes mangled to underscores the same way DTrace mangles them
+    /// when generating C symbols and macros.
+    pub fn mangled_name(&self) -> String {
+        mangle(&self.name)
+    }
+}
+
+/// A parsed D file, containing the providers it defines.
+#[derive(Debug, Clone)]
+pub struct File {
+    providers: Vec<Provider>,
+}
+
+impl FromStr for File {
+    type Err = Error;
+
+    fn from_str(contents: &str) -> Result<Self, Error> {
+        parse(contents)
+    }
+}
+
+impl File {
+    /// Read and parse a D provider definition file from disk.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)?;
+        contents.parse()
+    }
+
+    /// The providers this file defines, in declaration order.
+    pub fn providers(&self) -> &[Provider] {
+        &self.providers
+    }
+
+    /// Render this file's probes as Rust items: for each probe, an `extern "C"` block declaring
+    /// its firing and is-enabled symbols, plus two free functions, `<probe>_enabled()` and
+    /// `<probe>(...)`, named after the mangled probe name. No enclosing module is emitted.
+    pub fn to_rust_impl(&self) -> String {
+        let mut out = String::new();
+        for provider in &self.providers {
+            for probe in &provider.probes {
+                let args = probe
+                    .arguments
+                    .iter()
+                    .enumerate()
+                    .map(|(i, arg)| format!("arg{}: {}", i, arg.rust_type()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let arg_names = (0..probe.arguments.len())
+                    .map(|i| format!("arg{}", i))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                out.push_str(&format!(
+                    "extern \"C\" {{\n    fn {sym}({args});\n    fn {enabled_sym}() -> i32;\n}}\n\
+                     pub fn {probe}_enabled() -> bool {{ unsafe {{ {enabled_sym}() != 0 }} }}\n\
+                     pub fn {probe}({args}) {{ unsafe {{ {sym}({arg_names}); }} }}\n",
+                    sym = firing_symbol(provider, probe),
+                    enabled_sym = enabled_symbol(provider, probe),
+                    probe = probe.mangled_name(),
+                    args = args,
+                    arg_names = arg_names,
+                ));
+            }
+        }
+        out
+    }
+
+    /// Render the `extern` C declarations for every probe's underlying firing and is-enabled
+    /// symbols.
+    pub fn to_c_declaration(&self) -> String {
+        let mut out = String::new();
+        for provider in &self.providers {
+            for probe in &provider.probes {
+                out.push_str(&format!(
+                    "extern void {sym}({param_types});\nextern int {enabled_sym}(void);\n",
+                    sym = firing_symbol(provider, probe),
+                    enabled_sym = enabled_symbol(provider, probe),
+                    param_types = c_param_types(probe),
+                ));
+            }
+        }
+        out
+    }
+
+    /// Render the C definitions of every probe's underlying firing and is-enabled symbols. The
+    /// bodies are intentionally empty; `dtrace -G` rewrites the call sites DTrace cares about
+    /// after this is compiled.
+    pub fn to_c_definition(&self) -> String {
+        let mut out = String::new();
+        for provider in &self.providers {
+            for probe in &provider.probes {
+                out.push_str(&format!(
+                    "void {sym}({params}) {{}}\nint {enabled_sym}(void) {{ return 0; }}\n",
+                    sym = firing_symbol(provider, probe),
+                    enabled_sym = enabled_symbol(provider, probe),
+                    params = c_params(probe),
+                ));
+            }
+        }
+        out
+    }
+
+    /// Synthesize the same `#define`d macros that `dtrace -h` would emit for this file: an
+    /// `_ENABLED` test macro and a firing macro per probe, each expanding to the stable
+    /// `__dtrace_`-prefixed symbol also used by [`File::to_c_declaration`] and
+    /// [`File::to_c_definition`]. Generating this ourselves, rather than shelling out to
+    /// `dtrace -h`, lets callers produce the header without DTrace installed.
+    pub fn to_c_header(&self) -> String {
+        let mut out = String::from("#include <stdint.h>\n\n");
+        for provider in &self.providers {
+            for probe in &provider.probes {
+                let macro_name = format!(
+                    "{}_{}",
+                    provider.mangled_name().to_uppercase(),
+                    probe.mangled_name().to_uppercase()
+                );
+                let params = (0..probe.arguments.len())
+                    .map(|i| format!("arg{}", i))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                out.push_str(&format!(
+                    "#define {name}_ENABLED() {enabled_sym}()\n\
+                     #define {name}({params}) {sym}({params})\n\
+                     extern int {enabled_sym}(void);\n\
+                     extern void {sym}({param_types});\n\n",
+                    name = macro_name,
+                    sym = firing_symbol(provider, probe),
+                    enabled_sym = enabled_symbol(provider, probe),
+                    params = params,
+                    param_types = c_param_types(probe),
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// The stable C symbol DTrace uses to represent a probe's firing site.
+fn firing_symbol(provider: &Provider, probe: &Probe) -> String {
+    format!(
+        "__dtrace_{}___{}",
+        provider.mangled_name(),
+        probe.mangled_name()
+    )
+}
+
+/// The stable C symbol DTrace uses for a probe's is-enabled predicate.
+fn enabled_symbol(provider: &Provider, probe: &Probe) -> String {
+    format!(
+        "__dtrace_isenabled_{}___{}",
+        provider.mangled_name(),
+        probe.mangled_name()
+    )
+}
+
+fn c_param_types(probe: &Probe) -> String {
+    probe
+        .arguments
+        .iter()
+        .map(|arg| arg.c_type())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn c_params(probe: &Probe) -> String {
+    probe
+        .arguments
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| format!("{} arg{}", arg.c_type(), i))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parse the (deliberately small) subset of D provider definition syntax this crate supports:
+///
+/// ```text
+/// provider name {
+///     probe probe-name(type, type, ...);
+///     ...
+/// };
+/// ```
+fn parse(contents: &str) -> Result<File, Error> {
+    let without_comments = strip_comments(contents);
+    let mut rest = without_comments.as_str();
+    let mut providers = Vec::new();
+
+    while let Some(idx) = rest.find("provider") {
+        rest = &rest[idx + "provider".len()..];
+        let open_brace = rest
+            .find('{')
+            .ok_or_else(|| Error::Parse("expected '{' after provider name".to_string()))?;
+        let name = rest[..open_brace].trim().to_string();
+        if name.is_empty() {
+            return Err(Error::Parse("provider declared with no name".to_string()));
+        }
+        rest = &rest[open_brace + 1..];
+
+        let close_brace = rest
+            .find('}')
+            .ok_or_else(|| Error::Parse(format!("unterminated provider \"{}\"", name)))?;
+        let body = &rest[..close_brace];
+        rest = &rest[close_brace + 1..];
+
+        let mut probes = Vec::new();
+        for stmt in body.split(';') {
+            let stmt = stmt.trim();
+            if stmt.is_empty() {
+                continue;
+            }
+            let stmt = stmt.strip_prefix("probe").ok_or_else(|| {
+                Error::Parse(format!(
+                    "expected a `probe` declaration, found \"{}\"",
+                    stmt
+                ))
+            })?;
+            let open_paren = stmt
+                .find('(')
+                .ok_or_else(|| Error::Parse(format!("expected '(' in \"{}\"", stmt)))?;
+            let probe_name = stmt[..open_paren].trim().to_string();
+            if probe_name.is_empty() {
+                return Err(Error::Parse(format!(
+                    "probe declared with no name in provider \"{}\"",
+                    name
+                )));
+            }
+            let close_paren = stmt
+                .rfind(')')
+                .ok_or_else(|| Error::Parse(format!("expected ')' in \"{}\"", stmt)))?;
+            let arguments = stmt[open_paren + 1..close_paren]
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|ty| {
+                    DataType::from_d_type(ty)
+                        .map(|ty| Argument { ty })
+                        .ok_or_else(|| Error::Parse(format!("unsupported D argument type \"{}\"", ty)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            probes.push(Probe {
+                name: probe_name,
+                arguments,
+            });
+        }
+        providers.push(Provider { name, probes });
+    }
+
+    if providers.is_empty() {
+        return Err(Error::Parse(
+            "no `provider` declarations found in file".to_string(),
+        ));
+    }
+    Ok(File { providers })
+}
+
+fn strip_comments(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| match line.find("//") {
+            Some(idx) => &line[..idx],
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_basic_provider() {
+        let file = File::from_str(
+            r#"
+            provider my_provider {
+                probe my_probe(char *, int32_t);
+                probe other_probe();
+            };
+            "#,
+        )
+        .expect("Should parse a well-formed provider file");
+        assert_eq!(file.providers().len(), 1);
+        let provider = &file.providers()[0];
+        assert_eq!(provider.name(), "my_provider");
+        assert_eq!(provider.probes().len(), 2);
+        assert_eq!(provider.probes()[0].name(), "my_probe");
+        assert_eq!(provider.probes()[0].arguments().len(), 2);
+        assert!(provider.probes()[1].arguments().is_empty());
+    }
+
+    #[test]
+    fn mangles_dashes_in_names() {
+        let file = File::from_str(
+            r#"
+            provider my-provider {
+                probe op-start();
+            };
+            "#,
+        )
+        .expect("Should parse a provider file with dashed names");
+        let provider = &file.providers()[0];
+        assert_eq!(provider.name(), "my-provider");
+        assert_eq!(provider.mangled_name(), "my_provider");
+        assert_eq!(provider.probes()[0].mangled_name(), "op_start");
+    }
+
+    #[test]
+    fn guards_against_c_keyword_collisions() {
+        let file = File::from_str(
+            r#"
+            provider int {
+                probe for();
+            };
+            "#,
+        )
+        .expect("Should parse a provider file that collides with C keywords once mangled");
+        let provider = &file.providers()[0];
+        assert_eq!(provider.mangled_name(), "int_");
+        assert_eq!(provider.probes()[0].mangled_name(), "for_");
+    }
+
+    #[test]
+    fn header_defines_macros_named_after_mangled_names() {
+        let file = File::from_str(
+            r#"
+            provider my-provider {
+                probe op-start(char *, int32_t);
+            };
+            "#,
+        )
+        .unwrap();
+        let header = file.to_c_header();
+        assert!(header.contains("#define MY_PROVIDER_OP_START_ENABLED()"));
+        assert!(header.contains("#define MY_PROVIDER_OP_START(arg0, arg1)"));
+        assert!(header.contains("__dtrace_my_provider___op_start"));
+    }
+
+    #[test]
+    fn rejects_unsupported_argument_types() {
+        let err = File::from_str(
+            r#"
+            provider my_provider {
+                probe my_probe(some_weird_type);
+            };
+            "#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+    }
+}
@@ -6,7 +6,6 @@ use std::io::Write;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
-use dtrace_parser;
 use quote::quote;
 use structopt::StructOpt;
 
@@ -25,6 +24,15 @@ enum Cmd {
         #[structopt(short, long, default_value = "file", possible_values = &["file", "stdout"])]
         emit: String,
 
+        /// Make the emitted script abort with a precise diagnostic instead of a bare assertion
+        /// failure if `dtrace` is missing or exits non-zero.
+        ///
+        /// With `--strict`, the script captures the stderr of every external command it runs
+        /// and, on failure, aborts the build naming the offending provider file and the command's
+        /// captured output, distinguishing a missing `dtrace` binary from one that simply failed.
+        #[structopt(long)]
+        strict: bool,
+
         /// The source D file to be parsed.
         #[structopt(parse(from_str))]
         source: PathBuf,
@@ -41,9 +49,17 @@ enum Cmd {
         #[structopt(parse(from_str))]
         source: PathBuf,
     },
+    /// Compile the generated C header, declaration, definition, and probe call sites, to catch
+    /// any C signature mismatch between them. The objects are archived, not linked, and the
+    /// generated Rust bindings are not checked.
+    Verify {
+        /// The source D file to be parsed.
+        #[structopt(parse(from_str))]
+        source: PathBuf,
+    },
 }
 
-fn print_build_script(emit: &str, source: PathBuf) {
+fn print_build_script(emit: &str, strict: bool, source: PathBuf) {
     let source = source
         .canonicalize()
         .expect("Could not canonicalize provider file");
@@ -67,10 +83,70 @@ fn print_build_script(emit: &str, source: PathBuf) {
         ),
         String::from("#include <stdint.h>"),
         format!("#include \"{}\"\n", header_name),
-        format!("{}", dfile.to_c_definition()),
+        dfile.to_c_definition(),
+    ]
+    .join("\n");
+
+    // Generate the C header directly, rather than shelling out to `dtrace -h`. This is one of
+    // the two places DTrace itself is required to build this crate's consumers; synthesizing
+    // the header ourselves means it works in offline or vendored builds.
+    let header_source = &[
+        format!(
+            "// Autogenerated C header for DTrace probes in \"{}\"\n",
+            source.to_str().unwrap()
+        ),
+        dfile.to_c_header(),
     ]
     .join("\n");
 
+    // In strict mode, wait on `dtrace -G` and surface its captured stderr (and whether the
+    // binary was missing entirely) naming this provider file; otherwise keep the plainer
+    // assertion so non-strict builds don't change their output shape.
+    //
+    // The `Command` construction itself is identical between the two modes, so it's built once
+    // as `dtrace_g_command` and only the differing success-check tail varies, rather than
+    // duplicating the whole invocation in both branches.
+    let dtrace_g_command = quote! {
+        let width_flag = if target_pointer_width == "64" { "-64" } else { "-32" };
+        let mut dtrace_g = Command::new("dtrace");
+        dtrace_g
+            .arg(width_flag)
+            .arg("-G")
+            .arg("-s")
+            .arg(#source_filename)
+            .arg(&c_object_path)
+            .arg("-o")
+            .arg(&d_object_path);
+    };
+    let dtrace_g_result = if strict {
+        quote! {
+            match dtrace_g.output() {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => panic!(
+                    "`dtrace -G` failed while building probes for \"{}\":\n{}",
+                    #source_filename,
+                    String::from_utf8_lossy(&output.stderr),
+                ),
+                Err(e) => panic!(
+                    "Could not run `dtrace -G` while building probes for \"{}\": {}. Is DTrace installed?",
+                    #source_filename,
+                    e,
+                ),
+            }
+        }
+    } else {
+        quote! {
+            let status = dtrace_g
+                .status()
+                .expect("Failed to run DTrace against compiled source file");
+            assert!(status.success(), "`dtrace -G` exited with {}", status);
+        }
+    };
+    let dtrace_g_block = quote! {
+        #dtrace_g_command
+        #dtrace_g_result
+    };
+
     let script = quote! {
         //! Autogenerated build.rs script to generate Rust-C-DTrace glue.
         use std::process::Command;
@@ -87,49 +163,59 @@ fn print_build_script(emit: &str, source: PathBuf) {
             let c_object_path = out_dir.join(#c_object_name).to_str().unwrap().to_string();
             let d_object_path = out_dir.join(#d_object_name).to_str().unwrap().to_string();
 
-            // Generate a header file for the provider, placing it in OUT_DIR
-            Command::new("dtrace")
-                 .arg("-h")
-                 .arg("-s")
-                 .arg(#source_filename)
-                 .arg("-o")
-                 .arg(header_path)
-                 .output()
-                 .expect("Failed to generate header from provider file");
+            // This build script is always compiled and run on the host, but `cfg!(target_os =
+            // ...)` inside it would reflect the *host*, not the target being built for. Cargo
+            // exposes the actual target through these environment variables, so use them
+            // instead everywhere a host/target distinction matters.
+            let host = env::var("HOST").unwrap();
+            let target = env::var("TARGET").unwrap();
+            let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+            let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+            let target_pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
+
+            // Write out the generated header, placing it in OUT_DIR. This used to shell out to
+            // `dtrace -h`, but the header is just as easily synthesized from the parsed provider,
+            // so we write it alongside the C implementation below instead.
+            fs::write(&header_path, #header_source).expect("Could not write C header file");
 
             // Write out the C implementation, also in OUT_DIR
             fs::write(&source_path, #c_source).expect("Could not write C wrapper source file");
 
-            // Compile the autogenerated C source
+            // Compile the autogenerated C source, making sure the cross-compiler for `target`
+            // is selected rather than whatever `cc` would otherwise guess from the host.
             cc::Build::new()
                 .cargo_metadata(false)
                 .file(&source_path)
                 .include(&out_dir)
+                .target(&target)
+                .host(&host)
                 .compile(#c_object_name);
 
             // Run `dtrace -G -s provider.d source.o`. This generates a provider.o object, which
             // contains all the DTrace machinery to register the probes with the kernel. It also
             // modifies source.o, replacing the call instructions for any defined probes with NOP
-            // instructions. Note that this step is not required on macOS systems.
-            #[cfg(not(target_os = "macos"))]
-            Command::new("dtrace")
-                .arg("-G")
-                .arg("-s")
-                .arg(#source_filename)
-                .arg(&c_object_path)
-                .arg("-o")
-                .arg(&d_object_path)
-                .spawn()
-                .expect("Failed to run DTrace against compiled source file");
+            // instructions. Note that this step is not required when *targeting* macOS; keying
+            // this off `target_os`, rather than the host-only `cfg!(target_os = "macos")`, is
+            // what makes cross-compiling to or from macOS work.
+            if target_os != "macos" {
+                #dtrace_g_block
+            }
 
             // Generate a static library from all the above artifacts.
-            if cfg!(target_os = "macos") {
-                cc::Build::new()
-                    .object(&c_object_path)
-                    .compile(#lib_name);
+            let mut lib = cc::Build::new();
+            lib.target(&target).host(&host);
+            if target_os == "macos" {
+                // Apple's `-arch` flag calls 64-bit ARM `arm64` where Rust's `target_arch` says
+                // `aarch64`, so translate that one name; any other `target_arch` is forwarded
+                // to `-arch` unchanged.
+                let apple_arch = match target_arch.as_str() {
+                    "aarch64" => "arm64",
+                    other => other,
+                };
+                lib.flag("-arch").flag(apple_arch);
+                lib.object(&c_object_path).compile(#lib_name);
             } else {
-                cc::Build::new()
-                    .object(&c_object_path)
+                lib.object(&c_object_path)
                     .object(&d_object_path)
                     .compile(#lib_name);
             }
@@ -149,7 +235,7 @@ fn print_build_script(emit: &str, source: PathBuf) {
         fmt.stdin
             .take()
             .unwrap()
-            .write(script.as_bytes())
+            .write_all(script.as_bytes())
             .expect("Could not write rustfmt input");
         String::from_utf8(fmt.wait_with_output().unwrap().stdout).unwrap()
     } else {
@@ -162,6 +248,112 @@ fn print_build_script(emit: &str, source: PathBuf) {
     }
 }
 
+/// Return a placeholder value of the given C type, suitable for passing to a probe macro.
+fn dummy_c_value(c_type: &str) -> &'static str {
+    match c_type.trim() {
+        "char *" | "const char *" => "\"\"",
+        "float" => "0.0f",
+        "double" => "0.0",
+        _ => "0",
+    }
+}
+
+/// The triple of the host this binary is running on. `cc::Build` normally reads this (and the
+/// target) from `TARGET`/`HOST`, which Cargo only sets for build scripts; `usdt verify` runs as
+/// a plain binary, so ask `rustc` directly instead.
+fn host_triple() -> String {
+    let output = Command::new("rustc")
+        .arg("-vV")
+        .output()
+        .expect("Failed to run `rustc -vV` to determine the host triple");
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .expect("Could not find a `host:` line in `rustc -vV` output")
+        .to_string()
+}
+
+/// Compile the generated C declaration and definition together with a tiny harness that fires
+/// every probe, so that any mismatch between them surfaces as a compiler diagnostic instead of
+/// as a silently-dropped probe at runtime. The compiled objects are archived into a static
+/// library but not linked into an executable, so undefined symbols are not reported.
+fn verify_provider(source: PathBuf) {
+    let source = source
+        .canonicalize()
+        .expect("Could not canonicalize provider file");
+    let dfile = dtrace_parser::File::from_file(&source).expect("Could not parse DTrace file");
+
+    let dir = tempfile::tempdir().expect("Could not create temporary directory for verification");
+    let header_path = dir.path().join("decl.h");
+    let wrapper_path = dir.path().join("wrapper.c");
+    let harness_path = dir.path().join("harness.c");
+
+    // The header is what actually defines the `PROVIDER_PROBE(...)`/`..._ENABLED()` macros the
+    // harness below calls; the declaration and definition are the extern prototypes and bodies
+    // those macros expand to.
+    fs::write(&header_path, dfile.to_c_header()).expect("Could not write C header file");
+    let wrapper_source = format!(
+        "#include \"decl.h\"\n{}\n{}",
+        dfile.to_c_declaration(),
+        dfile.to_c_definition()
+    );
+    fs::write(&wrapper_path, &wrapper_source).expect("Could not write C wrapper source file");
+
+    let mut harness_source = String::from("#include \"decl.h\"\n\nint main(void) {\n");
+    for provider in dfile.providers() {
+        for probe in provider.probes() {
+            // Reuse the same dash-to-underscore mangling the header's macro names are built
+            // from, rather than re-deriving it here: a probe or provider name containing a dash
+            // would otherwise produce an invalid C token and make this harness fail to compile
+            // for glue that is actually fine.
+            let macro_name = format!(
+                "{}_{}",
+                provider.mangled_name().to_uppercase(),
+                probe.mangled_name().to_uppercase()
+            );
+            let args = probe
+                .arguments()
+                .iter()
+                .map(|arg| dummy_c_value(arg.c_type()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            harness_source.push_str(&format!(
+                "    if ({name}_ENABLED()) {{ {name}({args}); }}\n",
+                name = macro_name,
+                args = args,
+            ));
+        }
+    }
+    harness_source.push_str("    return 0;\n}\n");
+    fs::write(&harness_path, &harness_source).expect("Could not write C test harness file");
+
+    let host = host_triple();
+    match cc::Build::new()
+        .cargo_metadata(false)
+        .out_dir(dir.path())
+        .target(&host)
+        .host(&host)
+        .opt_level(0)
+        .file(&wrapper_path)
+        .file(&harness_path)
+        .try_compile("usdt_verify")
+    {
+        Ok(()) => println!(
+            "OK: generated FFI glue for \"{}\" is internally consistent",
+            source.display()
+        ),
+        Err(e) => {
+            eprintln!(
+                "Verification failed for \"{}\": the generated C declaration, definition, and \
+                 probe call sites do not agree\n{}",
+                source.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
 fn print_formatted_output(format: &str, source: PathBuf) {
     let file = dtrace_parser::File::from_file(&source).expect("Could not parse DTrace file");
     println!(
@@ -178,7 +370,12 @@ fn print_formatted_output(format: &str, source: PathBuf) {
 fn main() {
     let cmd = Cmd::from_args();
     match cmd {
-        Cmd::Buildgen { emit, source } => print_build_script(&emit, source),
+        Cmd::Buildgen {
+            emit,
+            strict,
+            source,
+        } => print_build_script(&emit, strict, source),
         Cmd::Fmt { format, source } => print_formatted_output(&format, source),
+        Cmd::Verify { source } => verify_provider(source),
     }
 }
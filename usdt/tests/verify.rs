@@ -0,0 +1,32 @@
+//! Exercise the `verify` subcommand against fixture provider files.
+// Copyright 2021 Oxide Computer Company
+
+use std::path::Path;
+use std::process::Command;
+
+fn run_verify(fixture: &str) -> std::process::ExitStatus {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(fixture);
+    Command::new(env!("CARGO_BIN_EXE_usdt"))
+        .arg("verify")
+        .arg(&source)
+        .status()
+        .expect("Failed to run `usdt verify`")
+}
+
+#[test]
+fn verify_succeeds_for_well_formed_provider() {
+    assert!(
+        run_verify("test.d").success(),
+        "verify should succeed for a well-formed provider"
+    );
+}
+
+#[test]
+fn verify_succeeds_for_dashed_names() {
+    // Regression test: provider/probe names containing dashes must be mangled consistently
+    // between the generated header's macros and the harness that calls them.
+    assert!(
+        run_verify("dashed-names.d").success(),
+        "verify should succeed for providers/probes whose names contain dashes"
+    );
+}